@@ -1,9 +1,13 @@
+use core::convert::Infallible;
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use common::{IO_BASE, states};
 use volatile::prelude::*;
 use volatile::{Volatile, WriteVolatile, ReadVolatile, Reserved};
 
+use embedded_hal::digital::v2::{InputPin, OutputPin, StatefulOutputPin, toggleable};
+
 /// An alternative GPIO function.
 #[repr(u8)]
 pub enum Function {
@@ -60,7 +64,7 @@ states! {
 /// `into_alt` methods before it can be used.
 pub struct Gpio<State> {
     pin: u8,
-    registers: &'static mut Registers,
+    registers: *mut Registers,
     _state: PhantomData<State>
 }
 
@@ -79,21 +83,57 @@ impl<T> Gpio<T> {
             _state: PhantomData
         }
     }
+
+    /// Reborrows the register block for the duration of a single register
+    /// access. `registers` is a raw pointer rather than a stored `&mut`
+    /// specifically so that no two live `Gpio`s can ever hold overlapping
+    /// `&mut Registers` at once; each access reborrows fresh and the
+    /// reference is gone again before the next call.
+    #[inline(always)]
+    fn regs(&self) -> &mut Registers {
+        unsafe { &mut *self.registers }
+    }
+
+    /// Returns the `(bank index, bit mask)` pair addressing this pin within
+    /// one of the paired 32-bit banked registers (`SET`, `CLR`, `LEV`,
+    /// `EDS`, `REN`, `FEN`, `HEN`, `LEN`, `AREN`, `AFEN`, `PUDCLK`).
+    #[inline(always)]
+    fn bank(&self) -> (usize, u32) {
+        let index = (self.pin / 32) as usize;
+        let offset = (self.pin as u32) - (32 * index as u32);
+        (index, 1 << offset)
+    }
+}
+
+/// Busy-waits for approximately `cycles` iterations.
+///
+/// Used by the `PUD`/`PUDCLK` sequence below, which requires a settling
+/// delay between writes so the hardware has time to latch the control
+/// signal on the clock edge.
+#[inline(always)]
+fn spin(cycles: u32) {
+    for _ in 0..cycles {
+        core::hint::spin_loop();
+    }
 }
 
 impl Gpio<Uninitialized> {
     /// Returns a new `GPIO` structure for pin number `pin`.
     ///
+    /// Not public: every pin must be obtained exactly once, through
+    /// `GpioPeripheral::split()`'s `Pins`, so callers can't end up with two
+    /// independent `Gpio`s racing to configure the same physical pin.
+    ///
     /// # Panics
     ///
     /// Panics if `pin` > `53`.
-    pub fn new(pin: u8) -> Gpio<Uninitialized> {
+    pub(crate) fn new(pin: u8) -> Gpio<Uninitialized> {
         if pin > 53 {
             panic!("Gpio::new(): pin {} exceeds maximum of 53", pin);
         }
 
         Gpio {
-            registers: unsafe { &mut *(GPIO_BASE as *mut Registers) },
+            registers: GPIO_BASE as *mut Registers,
             pin: pin,
             _state: PhantomData
         }
@@ -103,15 +143,15 @@ impl Gpio<Uninitialized> {
     /// and returns a `Gpio` structure in the `Alt` state.
     pub fn into_alt(self, function: Function) -> Gpio<Alt> {
         let fsel_index = (self.pin / 10) as usize;
-        // Get the appropirate offset for the 
+        // Get the appropirate offset for the
         // 3 bit slot used for FSEL[self.pin].
         let offset = (self.pin as usize) - fsel_index * 10;
-        // Payload to ensure `function` is set for that pin in FSEL 
+        // Payload to ensure `function` is set for that pin in FSEL
         let payload = function as u32;
         // Or with the payload to turn on in memory
-        self.registers.FSEL[fsel_index].or_mask(payload << (3 * offset));
+        self.regs().FSEL[fsel_index].or_mask(payload << (3 * offset));
         // Transition into the Alt state
-        // Don't have to annotate the type as rust can infer 
+        // Don't have to annotate the type as rust can infer
         // that from the return type of the function
         self.transition()
     }
@@ -137,20 +177,58 @@ impl Gpio<Output> {
         let offset = (self.pin as usize) - (32 * set_index);
         let payload = 1 as u32;
         // Turn on bit at offset
-        self.registers.SET[set_index].write(payload << offset);
+        self.regs().SET[set_index].write(payload << offset);
     }
 
     /// Clears (turns off) the pin.
     pub fn clear(&mut self) {
-        // Get index of the relevant 32 bits
-        let clr_index = (self.pin / 32) as usize;
-        let offset = (self.pin as usize) - (32 * clr_index);
-        let payload = 1 as u32;
-        // turn off bit at offset
-        self.registers.SET[clr_index].write(!(payload << offset));
+        // `SET`/`CLR` are one-hot write-only banks, not read-modify-write
+        // registers: writing any other pin's bit here would turn that pin
+        // off too, so the bit must go to `CLR`, never an inverted mask
+        // written back into `SET`.
+        let (index, mask) = self.bank();
+        self.regs().CLR[index].write(mask);
     }
 }
 
+impl OutputPin for Gpio<Output> {
+    type Error = Infallible;
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.set();
+        Ok(())
+    }
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.clear();
+        Ok(())
+    }
+}
+
+impl StatefulOutputPin for Gpio<Output> {
+    /// `SET`/`CLR` are write-only, so the currently-driven level is read
+    /// back from `LEV` instead of being cached on the struct.
+    fn is_set_high(&self) -> Result<bool, Infallible> {
+        let (index, mask) = self.bank();
+        Ok(self.regs().LEV[index].read() & mask != 0)
+    }
+
+    fn is_set_low(&self) -> Result<bool, Infallible> {
+        self.is_set_high().map(|high| !high)
+    }
+}
+
+impl toggleable::Default for Gpio<Output> {}
+
+/// The pull-up/pull-down resistor configuration for an input pin, written
+/// into the 2-bit `PUD` control field.
+#[repr(u8)]
+pub enum Pull {
+    Off = 0b00,
+    Down = 0b01,
+    Up = 0b10
+}
+
 impl Gpio<Input> {
     /// Reads the pin's value. Returns `true` if the level is high and `false`
     /// if the level is low.
@@ -158,11 +236,290 @@ impl Gpio<Input> {
         let level_index = (self.pin / 32) as usize;
         let offset = (self.pin as usize) - (32 * level_index);
         let payload = 1 as u32;
-        let value = self.registers.LEV[level_index].read();
+        let value = self.regs().LEV[level_index].read();
 
         match value & payload {
             0 => false,
             _ => true
         }
     }
-}
\ No newline at end of file
+
+    /// Configures this pin's pull-up/pull-down resistor.
+    ///
+    /// Follows the BCM2837 `PUD`/`PUDCLK` programming sequence exactly: the
+    /// control value is written to `PUD`, held for a settling delay, then
+    /// clocked into this pin's `PUDCLK` bit, held again, and finally both
+    /// registers are cleared so the latch is not left armed for the next pin.
+    pub fn set_pull(&mut self, pull: Pull) {
+        let (index, mask) = self.bank();
+
+        self.regs().PUD.write(pull as u32);
+        spin(150);
+        self.regs().PUDCLK[index].write(mask);
+        spin(150);
+        self.regs().PUD.write(0);
+        self.regs().PUDCLK[index].write(0);
+    }
+
+    /// Enables detection of a rising edge (low-to-high transition) on this
+    /// pin. Detected events surface as set bits in `EDS`; use
+    /// `is_event_detected()` to poll and `clear_event()` to acknowledge.
+    pub fn enable_rising_edge(&mut self) {
+        let (index, mask) = self.bank();
+        self.regs().REN[index].or_mask(mask);
+    }
+
+    /// Enables detection of a falling edge (high-to-low transition) on this
+    /// pin.
+    pub fn enable_falling_edge(&mut self) {
+        let (index, mask) = self.bank();
+        self.regs().FEN[index].or_mask(mask);
+    }
+
+    /// Enables detection of a sustained high level on this pin.
+    pub fn enable_high_level(&mut self) {
+        let (index, mask) = self.bank();
+        self.regs().HEN[index].or_mask(mask);
+    }
+
+    /// Enables detection of a sustained low level on this pin.
+    pub fn enable_low_level(&mut self) {
+        let (index, mask) = self.bank();
+        self.regs().LEN[index].or_mask(mask);
+    }
+
+    /// Enables asynchronous rising edge detection. Unlike
+    /// `enable_rising_edge()`, the signal is not synchronized to the system
+    /// clock first, so edges narrower than one clock cycle are still caught.
+    pub fn enable_async_rising_edge(&mut self) {
+        let (index, mask) = self.bank();
+        self.regs().AREN[index].or_mask(mask);
+    }
+
+    /// Enables asynchronous falling edge detection; see
+    /// `enable_async_rising_edge()`.
+    pub fn enable_async_falling_edge(&mut self) {
+        let (index, mask) = self.bank();
+        self.regs().AFEN[index].or_mask(mask);
+    }
+
+    /// Returns `true` if an enabled event has been detected on this pin
+    /// since the last `clear_event()`.
+    pub fn is_event_detected(&mut self) -> bool {
+        let (index, mask) = self.bank();
+        self.regs().EDS[index].read() & mask != 0
+    }
+
+    /// Acknowledges this pin's detected event so future events can be
+    /// observed. The `EDS` bits are write-1-to-clear, so this writes the
+    /// pin's bit back rather than zeroing the register.
+    pub fn clear_event(&mut self) {
+        let (index, mask) = self.bank();
+        self.regs().EDS[index].write(mask);
+    }
+}
+
+impl InputPin for Gpio<Input> {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Infallible> {
+        let (index, mask) = self.bank();
+        Ok(self.regs().LEV[index].read() & mask != 0)
+    }
+
+    fn is_low(&self) -> Result<bool, Infallible> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+/// The mode a `DynGpio` is currently configured in, tracked at runtime
+/// instead of in the type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DynMode {
+    Input,
+    Output,
+    Alt
+}
+
+/// Returned when a `DynGpio` operation is attempted while the pin is in the
+/// wrong mode, e.g. calling `set()` on a pin currently configured as
+/// `Input`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModeError;
+
+/// A type-erased GPIO pin whose mode is tracked in a field rather than in
+/// the type, mirroring rp-hal's merged `Pin`/`DynPin`. Unlike the
+/// compile-time type-state `Gpio<State>` API, pins in different modes can
+/// be stored together, e.g. in a `[DynGpio; N]` array for a parallel bus.
+///
+/// A `DynGpio` is obtained by converting a configured `Gpio<Input>`,
+/// `Gpio<Output>` or `Gpio<Alt>` pin (`.into()`); an `Uninitialized` pin
+/// cannot be converted. It is never constructed directly, so it is still subject to the single-owner
+/// guarantee that `Pins` provides.
+pub struct DynGpio {
+    pin: u8,
+    mode: DynMode,
+    registers: *mut Registers
+}
+
+impl DynGpio {
+    #[inline(always)]
+    fn regs(&self) -> &mut Registers {
+        unsafe { &mut *self.registers }
+    }
+
+    #[inline(always)]
+    fn bank(&self) -> (usize, u32) {
+        let index = (self.pin / 32) as usize;
+        let offset = (self.pin as u32) - (32 * index as u32);
+        (index, 1 << offset)
+    }
+
+    fn set_function(&mut self, function: Function) {
+        let fsel_index = (self.pin / 10) as usize;
+        let offset = (self.pin as usize) - fsel_index * 10;
+        self.regs().FSEL[fsel_index].and_mask(!(0b111 << (3 * offset)));
+        self.regs().FSEL[fsel_index].or_mask((function as u32) << (3 * offset));
+    }
+
+    /// Reconfigures this pin as an input.
+    pub fn into_input(&mut self) -> &mut Self {
+        self.set_function(Function::Input);
+        self.mode = DynMode::Input;
+        self
+    }
+
+    /// Reconfigures this pin as an output.
+    pub fn into_output(&mut self) -> &mut Self {
+        self.set_function(Function::Output);
+        self.mode = DynMode::Output;
+        self
+    }
+
+    /// Reconfigures this pin for alternate function `function`.
+    pub fn into_alt(&mut self, function: Function) -> &mut Self {
+        self.set_function(function);
+        self.mode = DynMode::Alt;
+        self
+    }
+
+    /// Returns this pin's currently tracked mode.
+    pub fn mode(&self) -> DynMode {
+        self.mode
+    }
+
+    /// Sets (turns on) the pin.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ModeError` if this pin is not currently in `Output` mode.
+    pub fn set(&mut self) -> Result<(), ModeError> {
+        if self.mode != DynMode::Output {
+            return Err(ModeError);
+        }
+        let (index, mask) = self.bank();
+        self.regs().SET[index].write(mask);
+        Ok(())
+    }
+
+    /// Clears (turns off) the pin.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ModeError` if this pin is not currently in `Output` mode.
+    pub fn clear(&mut self) -> Result<(), ModeError> {
+        if self.mode != DynMode::Output {
+            return Err(ModeError);
+        }
+        let (index, mask) = self.bank();
+        self.regs().CLR[index].write(mask);
+        Ok(())
+    }
+
+    /// Reads the pin's level.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ModeError` if this pin is not currently in `Input` mode.
+    pub fn level(&mut self) -> Result<bool, ModeError> {
+        if self.mode != DynMode::Input {
+            return Err(ModeError);
+        }
+        let (index, mask) = self.bank();
+        Ok(self.regs().LEV[index].read() & mask != 0)
+    }
+}
+
+impl From<Gpio<Input>> for DynGpio {
+    fn from(gpio: Gpio<Input>) -> DynGpio {
+        DynGpio { pin: gpio.pin, mode: DynMode::Input, registers: gpio.registers }
+    }
+}
+
+impl From<Gpio<Output>> for DynGpio {
+    fn from(gpio: Gpio<Output>) -> DynGpio {
+        DynGpio { pin: gpio.pin, mode: DynMode::Output, registers: gpio.registers }
+    }
+}
+
+impl From<Gpio<Alt>> for DynGpio {
+    fn from(gpio: Gpio<Alt>) -> DynGpio {
+        DynGpio { pin: gpio.pin, mode: DynMode::Alt, registers: gpio.registers }
+    }
+}
+
+/// Proof-of-ownership token for the `GPIO` peripheral. At most one
+/// `GpioPeripheral` can exist for the life of the program (see `take()`),
+/// and `split()` consumes it to hand out all 54 pins exactly once via
+/// `Pins`, so two call sites can no longer end up configuring the same
+/// physical pin without realizing it.
+pub struct GpioPeripheral {
+    _private: ()
+}
+
+static GPIO_TAKEN: AtomicBool = AtomicBool::new(false);
+
+impl GpioPeripheral {
+    /// Takes ownership of the `GPIO` peripheral. Returns `None` if it has
+    /// already been taken.
+    pub fn take() -> Option<GpioPeripheral> {
+        let already_taken = GPIO_TAKEN.compare_exchange(
+            false, true, Ordering::SeqCst, Ordering::SeqCst
+        ).is_err();
+
+        if already_taken {
+            None
+        } else {
+            Some(GpioPeripheral { _private: () })
+        }
+    }
+}
+
+macro_rules! pins {
+    ($($field:ident = $number:expr),* $(,)?) => {
+        /// Every GPIO pin, each owning only its own pin number so it can be
+        /// configured independently of (and without aliasing) every other
+        /// pin. Obtained from `GpioPeripheral::split()`.
+        pub struct Pins {
+            $(pub $field: Gpio<Uninitialized>),*
+        }
+
+        impl GpioPeripheral {
+            /// Splits the peripheral into its 54 individual pins.
+            pub fn split(self) -> Pins {
+                Pins {
+                    $($field: Gpio::new($number)),*
+                }
+            }
+        }
+    };
+}
+
+pins! {
+    pin0 = 0, pin1 = 1, pin2 = 2, pin3 = 3, pin4 = 4, pin5 = 5, pin6 = 6, pin7 = 7, pin8 = 8,
+    pin9 = 9, pin10 = 10, pin11 = 11, pin12 = 12, pin13 = 13, pin14 = 14, pin15 = 15, pin16 = 16, pin17 = 17,
+    pin18 = 18, pin19 = 19, pin20 = 20, pin21 = 21, pin22 = 22, pin23 = 23, pin24 = 24, pin25 = 25, pin26 = 26,
+    pin27 = 27, pin28 = 28, pin29 = 29, pin30 = 30, pin31 = 31, pin32 = 32, pin33 = 33, pin34 = 34, pin35 = 35,
+    pin36 = 36, pin37 = 37, pin38 = 38, pin39 = 39, pin40 = 40, pin41 = 41, pin42 = 42, pin43 = 43, pin44 = 44,
+    pin45 = 45, pin46 = 46, pin47 = 47, pin48 = 48, pin49 = 49, pin50 = 50, pin51 = 51, pin52 = 52, pin53 = 53,
+}